@@ -1,8 +1,10 @@
 use crate::chunks::ChunkPointer;
 use crate::meta::{FieldReader, FieldWriter, MetaObjectField};
 
+use bitflags::bitflags;
 use dashmap::DashMap;
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
 use std::path::Path;
@@ -11,6 +13,42 @@ use std::time::UNIX_EPOCH;
 
 type DashSet<T> = DashMap<T, ()>;
 
+/// The type of filesystem node an [`Entry`] represents.
+///
+/// Everything other than [`NodeKind::File`] used to be flattened into a
+/// regular file on restore; tracking the kind lets restore recreate the
+/// correct node (symlink, fifo, device, ...) instead.
+#[derive(Hash, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum NodeKind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDev { major: u32, minor: u32 },
+    CharDev { major: u32, minor: u32 },
+}
+
+impl Default for NodeKind {
+    fn default() -> Self {
+        NodeKind::File
+    }
+}
+
+bitflags! {
+    /// Windows file attributes worth preserving across a backup/restore.
+    /// Mirrors the `FILE_ATTRIBUTE_*` bits reported by
+    /// `MetadataExt::file_attributes()`.
+    #[derive(Hash, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct WinAttributes: u32 {
+        const READONLY = 0x0000_0001;
+        const HIDDEN = 0x0000_0002;
+        const SYSTEM = 0x0000_0004;
+        const ARCHIVE = 0x0000_0020;
+        const REPARSE_POINT = 0x0000_0400;
+    }
+}
+
 #[derive(Hash, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Entry {
     pub unix_secs: u64,
@@ -23,17 +61,69 @@ pub struct Entry {
     pub readonly: bool,
     pub name: String,
 
+    pub kind: NodeKind,
+    pub symlink_target: Option<String>,
+
+    pub unix_dev: u64,
+    pub unix_ino: u64,
+    pub nlink: u64,
+
+    /// Extended attributes (`user.*`, `security.selinux`, ACLs stored as
+    /// xattrs, ...) captured verbatim so they survive a restore.
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+
+    pub unix_atime: i64,
+    pub unix_atime_nanos: i64,
+    pub unix_ctime: i64,
+    pub unix_ctime_nanos: i64,
+
+    /// Creation time (`btime`/`st_birthtime` on unix, the creation FILETIME
+    /// on Windows) when the platform reports one.
+    pub btime: Option<(u64, u32)>,
+
+    /// Windows file attributes; `None` on unix where they do not apply.
+    pub win_attributes: Option<WinAttributes>,
+
     pub chunks: Vec<(u64, Arc<ChunkPointer>)>,
 }
 
 impl Entry {
     #[cfg(windows)]
     pub fn from_file(file: &fs::File, path: impl AsRef<Path>) -> Result<Entry, Box<dyn Error>> {
+        use std::os::windows::fs::MetadataExt;
+
         let path = path.as_ref();
-        let metadata = file.metadata()?;
+
+        // `File::open` follows reparse points, so the open handle's metadata
+        // would never report a symlink. Stat the path itself to classify the
+        // node and, when it is a link, archive the link rather than its
+        // target.
+        let link_meta = fs::symlink_metadata(path)?;
+        let symlink_target = if link_meta.file_type().is_symlink() {
+            Some(fs::read_link(path)?.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+
+        let metadata = if symlink_target.is_some() {
+            link_meta
+        } else {
+            file.metadata()?
+        };
         let (unix_secs, unix_nanos) = to_unix_mtime(&metadata)?;
 
-        Ok(File {
+        let kind = if metadata.is_dir() {
+            NodeKind::Dir
+        } else if metadata.file_type().is_symlink() {
+            NodeKind::Symlink
+        } else {
+            NodeKind::File
+        };
+
+        let win_attributes = WinAttributes::from_bits_truncate(metadata.file_attributes());
+        let btime = filetime_to_unix(metadata.creation_time());
+
+        Ok(Entry {
             unix_secs,
             unix_nanos,
             unix_perm: 0,
@@ -42,7 +132,24 @@ impl Entry {
 
             size: metadata.len(),
             readonly: metadata.permissions().readonly(),
-            name: path.as_ref().to_str().unwrap().to_string(),
+            name: path.to_str().unwrap().to_string(),
+
+            kind,
+            symlink_target,
+
+            unix_dev: 0,
+            unix_ino: 0,
+            nlink: 0,
+
+            xattrs: BTreeMap::new(),
+
+            unix_atime: 0,
+            unix_atime_nanos: 0,
+            unix_ctime: 0,
+            unix_ctime_nanos: 0,
+
+            btime,
+            win_attributes: Some(win_attributes),
 
             chunks: Vec::new(),
         })
@@ -52,9 +159,26 @@ impl Entry {
     pub fn from_file(file: &fs::File, path: impl AsRef<Path>) -> Result<Entry, Box<dyn Error>> {
         use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
-        let metadata = file.metadata()?;
+        let path = path.as_ref();
+
+        // Stat the link itself rather than its target, so a symlink is
+        // archived as a symlink instead of following through to its
+        // destination.
+        let link_meta = fs::symlink_metadata(path)?;
+        let symlink_target = if link_meta.file_type().is_symlink() {
+            Some(fs::read_link(path)?.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+
+        let metadata = if symlink_target.is_some() {
+            link_meta
+        } else {
+            file.metadata()?
+        };
         let perms = metadata.permissions();
         let (unix_secs, unix_nanos) = to_unix_mtime(&metadata)?;
+        let kind = node_kind(&metadata);
 
         Ok(Entry {
             unix_secs,
@@ -65,11 +189,138 @@ impl Entry {
 
             size: metadata.len(),
             readonly: metadata.permissions().readonly(),
-            name: path.as_ref().to_str().unwrap().to_string(),
+            name: path.to_str().unwrap().to_string(),
+
+            kind: kind.clone(),
+            symlink_target,
+
+            unix_dev: metadata.dev(),
+            unix_ino: metadata.ino(),
+            nlink: metadata.nlink(),
+
+            // `xattr` follows symlinks, so capturing attributes for a
+            // symlink would read the target's. We archive the link itself,
+            // so its (rarely present) xattrs are skipped.
+            xattrs: if kind == NodeKind::Symlink {
+                BTreeMap::new()
+            } else {
+                read_xattrs(path)
+            },
+
+            unix_atime: metadata.atime(),
+            unix_atime_nanos: metadata.atime_nsec(),
+            unix_ctime: metadata.ctime(),
+            unix_ctime_nanos: metadata.ctime_nsec(),
+
+            btime: metadata
+                .created()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| (d.as_secs(), d.subsec_nanos())),
+            win_attributes: None,
 
             chunks: Vec::new(),
         })
     }
+
+    /// The `(device, inode)` identity used to recognise hard links to the
+    /// same underlying inode. Only meaningful when [`Entry::nlink`] > 1.
+    pub fn inode_id(&self) -> (u64, u64) {
+        (self.unix_dev, self.unix_ino)
+    }
+
+    /// Re-apply the captured extended attributes onto an already restored
+    /// `path`. Attributes the filesystem refuses are skipped rather than
+    /// aborting the restore: `ENOTSUP` when xattrs are unsupported, and
+    /// `EPERM`/`EACCES` for privileged namespaces (`security.*`,
+    /// `trusted.*`, ...) that an unprivileged restore cannot set.
+    ///
+    /// Symlink entries are skipped entirely: `xattr::set` follows the link
+    /// and would write onto its target (or fail with `ENOENT`).
+    #[cfg(unix)]
+    pub fn restore_xattrs(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        if self.kind == NodeKind::Symlink {
+            return Ok(());
+        }
+
+        let path = path.as_ref();
+        for (name, value) in &self.xattrs {
+            match xattr::set(path, name, value) {
+                Ok(()) => {}
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(libc::ENOTSUP) | Some(libc::EPERM) | Some(libc::EACCES)
+                    ) => {}
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn node_kind(m: &fs::Metadata) -> NodeKind {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let ft = m.file_type();
+    if ft.is_dir() {
+        NodeKind::Dir
+    } else if ft.is_symlink() {
+        NodeKind::Symlink
+    } else if ft.is_fifo() {
+        NodeKind::Fifo
+    } else if ft.is_socket() {
+        NodeKind::Socket
+    } else if ft.is_block_device() {
+        let (major, minor) = major_minor(m.rdev());
+        NodeKind::BlockDev { major, minor }
+    } else if ft.is_char_device() {
+        let (major, minor) = major_minor(m.rdev());
+        NodeKind::CharDev { major, minor }
+    } else {
+        NodeKind::File
+    }
+}
+
+/// Split a raw `st_rdev` value into (major, minor) using the glibc encoding.
+#[cfg(unix)]
+fn major_minor(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// Read every extended attribute attached to `path`. Filesystems that do
+/// not support xattrs (`ENOTSUP`) simply yield an empty map rather than
+/// failing the whole backup.
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> BTreeMap<String, Vec<u8>> {
+    let mut out = BTreeMap::new();
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(_) => return out,
+    };
+
+    for name in names {
+        if let Ok(Some(value)) = xattr::get(path, &name) {
+            out.insert(name.to_string_lossy().into_owned(), value);
+        }
+    }
+
+    out
+}
+
+/// Convert a Windows creation FILETIME (100 ns ticks since 1601-01-01) into
+/// a unix `(secs, nanos)` pair, returning `None` when the value predates the
+/// unix epoch.
+#[cfg(windows)]
+fn filetime_to_unix(filetime: u64) -> Option<(u64, u32)> {
+    // Number of 100 ns ticks between 1601-01-01 and 1970-01-01.
+    const EPOCH_DIFF: u64 = 116_444_736_000_000_000;
+    let ticks = filetime.checked_sub(EPOCH_DIFF)?;
+    Some((ticks / 10_000_000, ((ticks % 10_000_000) * 100) as u32))
 }
 
 fn to_unix_mtime(m: &fs::Metadata) -> Result<(u64, u32), Box<dyn Error>> {
@@ -78,40 +329,507 @@ fn to_unix_mtime(m: &fs::Metadata) -> Result<(u64, u32), Box<dyn Error>> {
 }
 
 pub type FileIndex = DashSet<Arc<Entry>>;
+type PathIndex = DashMap<String, Arc<Entry>>;
+
+/// Insert `file` into the index pair, superseding any prior version of the
+/// same path so a path never has more than one live entry within a
+/// generation.
+fn push_into(index: &FileIndex, by_path: &PathIndex, file: Entry) {
+    if let Some((_, old)) = by_path.remove(&file.name) {
+        index.remove(&old);
+    }
+
+    let entry = Arc::new(file);
+    index.insert(entry.clone(), ());
+    by_path.insert(entry.name.clone(), entry);
+}
+
+/// A single backup run. Generations form a chain through [`Generation::parent`],
+/// so a run can be listed on its own or diffed against any other, the way
+/// obnam tracks distinct generations in one repository.
+#[derive(Clone)]
+pub struct Generation {
+    pub id: u64,
+    pub timestamp: u64,
+    pub parent: Option<u64>,
+    index: Arc<FileIndex>,
+    by_path: Arc<PathIndex>,
+}
+
+impl Generation {
+    fn new(id: u64, timestamp: u64, parent: Option<u64>) -> Self {
+        Generation {
+            id,
+            timestamp,
+            parent,
+            index: Arc::new(DashMap::new()),
+            by_path: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn index(&self) -> &FileIndex {
+        &self.index
+    }
+
+    pub fn get_by_path(&self, path: &str) -> Option<Arc<Entry>> {
+        self.by_path.get(path).map(|e| e.value().clone())
+    }
+}
+
+/// The set of paths that changed between two generations.
+#[derive(Clone, Debug, Default)]
+pub struct GenerationDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
 
 #[derive(Clone, Default)]
-pub struct FileStore(Arc<FileIndex>);
+struct GenMeta {
+    id: u64,
+    timestamp: u64,
+    parent: Option<u64>,
+}
+
+/// One serialized record in the `files` field: either a generation header or
+/// an entry belonging to the most recently written header.
+#[derive(Serialize, Deserialize)]
+pub enum FileRecord {
+    Generation {
+        id: u64,
+        timestamp: u64,
+        parent: Option<u64>,
+    },
+    Entry(Entry),
+}
+
+#[derive(Clone, Default)]
+pub struct FileStore {
+    // The generation currently being built; `index`/`by_path` back the
+    // public `index()` accessor and the quick check.
+    index: Arc<FileIndex>,
+    by_path: Arc<PathIndex>,
+    meta: Arc<std::sync::RwLock<GenMeta>>,
+    // Sealed generations, oldest first.
+    history: Arc<std::sync::RwLock<Vec<Generation>>>,
+    // Maps the `(dev, ino)` of an already-indexed multiply-linked inode to
+    // the first path that archived its contents, so later hard links are
+    // recorded as references instead of re-reading the same chunks.
+    inodes: Arc<DashMap<(u64, u64), String>>,
+}
 
 impl FileStore {
     pub fn index(&self) -> &FileIndex {
-        &self.0
+        &self.index
+    }
+
+    /// The most recently pushed entry for `path` in the current generation.
+    pub fn get_by_path(&self, path: &str) -> Option<Arc<Entry>> {
+        self.by_path.get(path).map(|e| e.value().clone())
     }
 
+    /// rsync-style quick check: a file counts as unchanged when a prior
+    /// entry for the same path matches on `size` and mtime
+    /// (`unix_secs`/`unix_nanos`). Metadata-only differences (perms,
+    /// ownership, ...) do not force a re-chunk; they update the record in
+    /// place on the next `push`.
     pub fn has_changed(&self, file: &Entry) -> bool {
-        !self.0.contains_key(file)
+        match self.by_path.get(&file.name) {
+            Some(prev) => {
+                prev.size != file.size
+                    || prev.unix_secs != file.unix_secs
+                    || prev.unix_nanos != file.unix_nanos
+            }
+            None => true,
+        }
+    }
+
+    /// If `file` is a hard link (`nlink > 1`) to an inode already archived
+    /// in this store, return the path that holds the contents. Otherwise
+    /// register this entry as the first occurrence and return `None`,
+    /// meaning the caller should read and store its chunks normally.
+    pub fn hardlink_of(&self, file: &Entry) -> Option<String> {
+        if file.nlink <= 1 {
+            return None;
+        }
+
+        match self.inodes.entry(file.inode_id()) {
+            dashmap::mapref::entry::Entry::Occupied(e) => Some(e.get().clone()),
+            dashmap::mapref::entry::Entry::Vacant(e) => {
+                e.insert(file.name.clone());
+                None
+            }
+        }
     }
 
     pub fn push(&mut self, file: Entry) {
-        self.0.insert(Arc::new(file), ());
+        push_into(&self.index, &self.by_path, file);
+    }
+
+    /// Seal the generation currently being built and start a fresh one
+    /// linked back to it as its parent. Returns the id of the new current
+    /// generation. The `timestamp` is supplied by the caller since this
+    /// layer does not read the clock.
+    pub fn commit_generation(&mut self, timestamp: u64) -> u64 {
+        let sealed = {
+            let meta = self.meta.read().unwrap();
+            Generation {
+                id: meta.id,
+                timestamp: meta.timestamp,
+                parent: meta.parent,
+                index: self.index.clone(),
+                by_path: self.by_path.clone(),
+            }
+        };
+
+        let next_id = sealed.id + 1;
+
+        // Seed the new generation from its parent: every entry is carried
+        // forward so that the next run's quick check (`has_changed`) and
+        // hard-link dedup (`hardlink_of`) can see unchanged files and only
+        // re-read what actually changed. Each carried entry is a fresh
+        // `Arc` clone so the two generations' indexes stay independent.
+        let index = Arc::new(DashMap::new());
+        let by_path = Arc::new(DashMap::new());
+        self.inodes.clear();
+        for item in sealed.by_path.iter() {
+            let entry = item.value().clone();
+            if entry.nlink > 1 {
+                self.inodes
+                    .entry(entry.inode_id())
+                    .or_insert_with(|| entry.name.clone());
+            }
+            index.insert(entry.clone(), ());
+            by_path.insert(entry.name.clone(), entry);
+        }
+
+        self.history.write().unwrap().push(sealed.clone());
+        self.index = index;
+        self.by_path = by_path;
+        *self.meta.write().unwrap() = GenMeta {
+            id: next_id,
+            timestamp,
+            parent: Some(sealed.id),
+        };
+
+        next_id
+    }
+
+    fn current_generation(&self) -> Generation {
+        let meta = self.meta.read().unwrap();
+        Generation {
+            id: meta.id,
+            timestamp: meta.timestamp,
+            parent: meta.parent,
+            index: self.index.clone(),
+            by_path: self.by_path.clone(),
+        }
+    }
+
+    /// All generations in the chain, oldest first, with the in-progress
+    /// current generation last.
+    pub fn generations(&self) -> Vec<Generation> {
+        let mut gens = self.history.read().unwrap().clone();
+        gens.push(self.current_generation());
+        gens
+    }
+
+    /// The generation with the given id, if present in the chain.
+    pub fn generation(&self, id: u64) -> Option<Generation> {
+        self.generations().into_iter().find(|g| g.id == id)
+    }
+
+    /// Paths added, removed, or content-changed going from generation
+    /// `from` to generation `to`. Returns `None` if either id is unknown.
+    pub fn diff(&self, from: u64, to: u64) -> Option<GenerationDiff> {
+        let a = self.generation(from)?;
+        let b = self.generation(to)?;
+        Some(diff_generations(&a, &b))
+    }
+}
+
+/// Compute the paths added, removed, and content-changed going from
+/// generation `from` to generation `to`. A path counts as changed when its
+/// `size` or mtime (`unix_secs`/`unix_nanos`) differ, mirroring the
+/// quick-check in [`FileStore::has_changed`].
+fn diff_generations(from: &Generation, to: &Generation) -> GenerationDiff {
+    let mut diff = GenerationDiff::default();
+
+    for item in to.by_path.iter() {
+        match from.by_path.get(item.key()) {
+            None => diff.added.push(item.key().clone()),
+            Some(prev) => {
+                let cur = item.value();
+                if prev.size != cur.size
+                    || prev.unix_secs != cur.unix_secs
+                    || prev.unix_nanos != cur.unix_nanos
+                {
+                    diff.changed.push(item.key().clone());
+                }
+            }
+        }
+    }
+
+    for item in from.by_path.iter() {
+        if !to.by_path.contains_key(item.key()) {
+            diff.removed.push(item.key().clone());
+        }
+    }
+
+    diff
+}
+
+/// Tracks inodes already materialized on disk during a restore so that the
+/// second and later paths of a hard-linked inode are recreated with
+/// [`std::fs::hard_link`] rather than written out again.
+#[derive(Default)]
+pub struct HardLinkTracker(std::collections::HashMap<(u64, u64), std::path::PathBuf>);
+
+impl HardLinkTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `path` as the materialized location for `entry`'s inode,
+    /// returning the previously materialized path when this inode has
+    /// already been restored, so the caller can hard-link to it.
+    pub fn register(&mut self, entry: &Entry, path: impl AsRef<Path>) -> Option<std::path::PathBuf> {
+        if entry.nlink <= 1 {
+            return None;
+        }
+
+        match self.0.entry(entry.inode_id()) {
+            std::collections::hash_map::Entry::Occupied(e) => Some(e.get().clone()),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                e.insert(path.as_ref().to_path_buf());
+                None
+            }
+        }
     }
 }
 
 impl MetaObjectField for FileStore {
-    type Item = Entry;
+    type Item = FileRecord;
 
     fn key() -> String {
         "files".to_string()
     }
 
     fn serialize(&self, mw: &mut impl FieldWriter) {
-        for f in self.0.iter() {
-            mw.write_next(f.key());
+        for gen in self.generations() {
+            mw.write_next(FileRecord::Generation {
+                id: gen.id,
+                timestamp: gen.timestamp,
+                parent: gen.parent,
+            });
+            for f in gen.index.iter() {
+                mw.write_next(FileRecord::Entry((**f.key()).clone()));
+            }
         }
     }
 
     fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
-        while let Ok(file) = mw.read_next() {
-            self.0.insert(Arc::new(file), ());
+        // The `files` field is now a stream of `FileRecord`s, each
+        // generation introduced by a `Generation` header. This is a hard
+        // format break: archives written before generations stored bare
+        // `Entry` values and do not decode here — there is no migration
+        // path, by design. The fallback below only guards a malformed new
+        // archive whose first record is an entry; it is not old-format
+        // compatibility.
+        let mut gens: Vec<(GenMeta, Vec<Entry>)> = Vec::new();
+
+        while let Ok(record) = mw.read_next() {
+            match record {
+                FileRecord::Generation {
+                    id,
+                    timestamp,
+                    parent,
+                } => gens.push((
+                    GenMeta {
+                        id,
+                        timestamp,
+                        parent,
+                    },
+                    Vec::new(),
+                )),
+                FileRecord::Entry(entry) => {
+                    if gens.is_empty() {
+                        gens.push((GenMeta::default(), Vec::new()));
+                    }
+                    gens.last_mut().unwrap().1.push(entry);
+                }
+            }
+        }
+
+        // A well-formed archive always carries at least the trailing
+        // current-generation header, so an empty `gens` means the very
+        // first record failed to decode as a `FileRecord` — most likely a
+        // pre-generation archive of bare `Entry`s. Fail loudly rather than
+        // loading a silently-empty store that would restore zero files.
+        let (cur_meta, cur_entries) = match gens.pop() {
+            Some(current) => current,
+            None => panic!(
+                "files field did not decode as a generation stream: \
+                 the archive predates multi-generation support and cannot be read"
+            ),
+        };
+
+        let history: Vec<Generation> = gens
+            .into_iter()
+            .map(|(meta, entries)| {
+                let gen = Generation::new(meta.id, meta.timestamp, meta.parent);
+                for entry in entries {
+                    push_into(&gen.index, &gen.by_path, entry);
+                }
+                gen
+            })
+            .collect();
+
+        *self.history.write().unwrap() = history;
+        *self.meta.write().unwrap() = cur_meta;
+        for entry in cur_entries {
+            push_into(&self.index, &self.by_path, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_entry(name: &str, size: u64, secs: u64) -> Entry {
+        Entry {
+            unix_secs: secs,
+            unix_nanos: 0,
+            unix_perm: 0,
+            unix_uid: 0,
+            unix_gid: 0,
+
+            size,
+            readonly: false,
+            name: name.to_string(),
+
+            kind: NodeKind::File,
+            symlink_target: None,
+
+            unix_dev: 0,
+            unix_ino: 0,
+            nlink: 1,
+
+            xattrs: BTreeMap::new(),
+
+            unix_atime: 0,
+            unix_atime_nanos: 0,
+            unix_ctime: 0,
+            unix_ctime_nanos: 0,
+
+            btime: None,
+            win_attributes: None,
+
+            chunks: Vec::new(),
         }
     }
+
+    // In-memory `FieldWriter`/`FieldReader` backed by JSON values, used to
+    // exercise the `files` field round-trip without the object store.
+    #[derive(Default)]
+    struct VecWriter {
+        records: Vec<serde_json::Value>,
+    }
+
+    impl FieldWriter for VecWriter {
+        fn write_next<T: serde::Serialize>(&mut self, item: T) {
+            self.records.push(serde_json::to_value(item).unwrap());
+        }
+    }
+
+    struct VecReader {
+        iter: std::vec::IntoIter<serde_json::Value>,
+    }
+
+    impl FieldReader<FileRecord> for VecReader {
+        fn read_next(&mut self) -> Result<FileRecord, Box<dyn Error>> {
+            match self.iter.next() {
+                Some(value) => Ok(serde_json::from_value(value)?),
+                None => Err("end of stream".into()),
+            }
+        }
+    }
+
+    #[test]
+    fn push_supersedes_same_path() {
+        let mut store = FileStore::default();
+        store.push(test_entry("a", 1, 1));
+        store.push(test_entry("a", 2, 2));
+
+        assert_eq!(store.index().len(), 1);
+        assert_eq!(store.get_by_path("a").unwrap().size, 2);
+    }
+
+    #[test]
+    fn diff_reports_added_removed_changed() {
+        let from = Generation::new(0, 0, None);
+        push_into(&from.index, &from.by_path, test_entry("keep", 1, 1));
+        push_into(&from.index, &from.by_path, test_entry("gone", 1, 1));
+        push_into(&from.index, &from.by_path, test_entry("edit", 1, 1));
+
+        let to = Generation::new(1, 0, Some(0));
+        push_into(&to.index, &to.by_path, test_entry("keep", 1, 1));
+        push_into(&to.index, &to.by_path, test_entry("edit", 2, 1));
+        push_into(&to.index, &to.by_path, test_entry("new", 1, 1));
+
+        let mut diff = diff_generations(&from, &to);
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+
+        assert_eq!(diff.added, vec!["new".to_string()]);
+        assert_eq!(diff.removed, vec!["gone".to_string()]);
+        assert_eq!(diff.changed, vec!["edit".to_string()]);
+    }
+
+    #[test]
+    fn commit_generation_threads_id_and_parent() {
+        let mut store = FileStore::default();
+        assert_eq!(store.generation(0).unwrap().parent, None);
+
+        store.push(test_entry("a", 1, 1));
+        let id = store.commit_generation(99);
+
+        assert_eq!(id, 1);
+        let current = store.generation(1).unwrap();
+        assert_eq!(current.parent, Some(0));
+        assert_eq!(current.timestamp, 99);
+        // The unchanged entry is carried forward so the next run's quick
+        // check can recognise it.
+        assert!(store.get_by_path("a").is_some());
+    }
+
+    #[test]
+    fn filerecord_serialize_deserialize_round_trip() {
+        let mut store = FileStore::default();
+        store.push(test_entry("a", 1, 1));
+        store.commit_generation(42);
+        store.push(test_entry("b", 2, 2));
+
+        let mut writer = VecWriter::default();
+        store.serialize(&mut writer);
+
+        let restored = FileStore::default();
+        let mut reader = VecReader {
+            iter: writer.records.into_iter(),
+        };
+        restored.deserialize(&mut reader);
+
+        assert_eq!(restored.generations().len(), 2);
+
+        let gen0 = restored.generation(0).unwrap();
+        assert!(gen0.get_by_path("a").is_some());
+        assert!(gen0.get_by_path("b").is_none());
+
+        let gen1 = restored.generation(1).unwrap();
+        assert_eq!(gen1.parent, Some(0));
+        assert_eq!(gen1.timestamp, 42);
+        assert_eq!(gen1.get_by_path("b").unwrap().size, 2);
+    }
 }